@@ -1,12 +1,18 @@
+#![feature(allocator_api)]
 
-
+use std::alloc::Allocator;
+use std::alloc::Global;
 use std::marker::PhantomData;
 use std::ops::Deref;
 use std::ops::DerefMut;
+use std::ptr;
 use std::ptr::NonNull;
 
 pub trait IterTakeExt {
 	type Item;
+	/// The allocator of the backing [`Vec`], threaded through so the iterator
+	/// works for any `Vec<T, A>` and not just the global allocator.
+	type Alloc: Allocator;
 	/// # Basic usage
 	///
 	/// Creates an mutable iterator over [`Vec<T>`] that allows items to be removed while iterating
@@ -80,40 +86,127 @@ pub trait IterTakeExt {
 	/// # Panics
 	///
 	/// See [`IterTake::next`]: Panics
-	fn iter_take(&mut self) -> IterTake<Self::Item>;
+	fn iter_take(&mut self) -> IterTake<'_, Self::Item, Self::Alloc>;
+
+	/// Creates an iterator that removes and yields the elements for which
+	/// `pred` returns `true`, leaving the rest in the [`Vec`] in their original
+	/// order.
+	///
+	/// This is the filter-and-remove shorthand for
+	/// `iter_take().filter(..).map(Take::take)`, modeled on the standard
+	/// library's `extract_if`. Because it owns the compaction cursors directly
+	/// there is never more than one element in flight, so the aliasing panic of
+	/// [`IterTake`] cannot be hit. A full pass is `O(n)` even when every element
+	/// matches, and dropping the [`ExtractTake`] early still shifts the
+	/// unvisited tail back into place.
+	///
+	/// ```
+	/// use bad_take::IterTakeExt;
+	///
+	/// let mut numbers = vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+	///
+	/// let evens: Vec<i32> = numbers.extract_take(|i| *i % 2 == 0).collect();
+	///
+	/// assert_eq!(evens, vec![0, 2, 4, 6, 8]);
+	/// assert_eq!(numbers, vec![1, 3, 5, 7, 9]);
+	/// ```
+	fn extract_take<F: FnMut(&mut Self::Item) -> bool>(
+		&mut self,
+		pred: F,
+	) -> ExtractTake<'_, Self::Item, F, Self::Alloc>;
 }
 
-impl<T> IterTakeExt for Vec<T> {
+impl<T, A: Allocator> IterTakeExt for Vec<T, A> {
 	type Item = T;
+	type Alloc = A;
 
-	fn iter_take(&mut self) -> IterTake<Self::Item> {
+	fn iter_take(&mut self) -> IterTake<'_, Self::Item, Self::Alloc> {
 		IterTake::new(self)
 	}
+
+	fn extract_take<F: FnMut(&mut Self::Item) -> bool>(
+		&mut self,
+		pred: F,
+	) -> ExtractTake<'_, Self::Item, F, Self::Alloc> {
+		ExtractTake::new(self, pred)
+	}
 }
 
-pub struct IterTake<'a, T> {
-	inner: NonNull<Vec<T>>,
-	index: usize,
+/// Order-preserving removal in a single linear pass.
+///
+/// The iterator borrows the [`Vec`] and immediately sets its length to `0` so
+/// that an unwind part-way through can never expose a half-moved buffer. The
+/// elements are then compacted in place with a pair of read/write cursors at
+/// each end — the same deferred-compaction trick the standard library uses for
+/// `extract_if`: kept elements are shifted into the gap left behind by taken
+/// ones, and whatever is still untouched is moved back into place when the
+/// iterator is dropped. Implementing [`DoubleEndedIterator`] means consumption
+/// can come from the front, the back, or both, meeting in the middle.
+pub struct IterTake<'a, T, A: Allocator = Global> {
+	guard: DropGuard<'a, T, A>,
 
 	/// NOTE: this is needed to stop multiple `Take`s from aliasing
 	panic: bool,
+}
+
+/// Owns the compaction cursors so that the back-shift always finishes.
+///
+/// Whether iteration ends normally or a caller's closure unwinds while a
+/// [`Take`] is live, this guard's [`Drop`] shifts the still-unvisited middle
+/// (`read..back`) down onto the write cursor, slides the elements kept from the
+/// back up against it, and publishes a correct length, leaving the [`Vec`] safe
+/// to observe and drop. It mirrors the `BackshiftOnDrop` guard the standard
+/// library uses inside `extract_if`.
+///
+/// The buffer is kept partitioned as
+/// `[0, write)` front-kept · `[write, read)` gap · `[read, back)` untouched ·
+/// `[back, rwrite)` gap · `[rwrite, orig_len)` back-kept, so front and back
+/// consumption compact towards each other from opposite ends.
+struct DropGuard<'a, T, A: Allocator = Global> {
+	inner: NonNull<Vec<T, A>>,
+
+	/// Index of the element [`Iterator::next`] will yield next.
+	read: usize,
+	/// Index the next front-kept element is shifted down to; `write <= read`.
+	write: usize,
+	/// One past the element [`DoubleEndedIterator::next_back`] will yield next.
+	back: usize,
+	/// Index the next back-kept element is shifted up to; `back <= rwrite`.
+	rwrite: usize,
+	/// Length the [`Vec`] had when iteration started.
+	orig_len: usize,
 
 	_item: PhantomData<&'a mut T>,
 }
 
-impl<'a, T> IterTake<'a, T> {
-	pub(crate) fn new(inner: &'a mut Vec<T>) -> Self {
+impl<'a, T, A: Allocator> IterTake<'a, T, A> {
+	pub(crate) fn new(inner: &'a mut Vec<T, A>) -> Self {
+		let orig_len = inner.len();
+
+		// Take ownership of the tail: with the length at `0` the `Vec` owns
+		// nothing, so it never points at elements we may have already moved or
+		// read out. When the iterator is dropped, on a normal finish or an
+		// unwind, the `DropGuard` restores the length; if it is leaked instead,
+		// the `Vec` is left empty-but-valid.
+		unsafe { inner.set_len(0) }
+
 		Self {
-			inner: NonNull::from(inner),
-			index: 0,
+			guard: DropGuard {
+				inner: NonNull::from(inner),
+				read: 0,
+				write: 0,
+				back: orig_len,
+				rwrite: orig_len,
+				orig_len,
+				_item: PhantomData,
+			},
 			panic: false,
-			_item: PhantomData,
 		}
 	}
 }
 
-impl<'a, T: 'a> Iterator for IterTake<'a, T> {
-	type Item = Take<'a, T>;
+impl<'a, T: 'a, A: Allocator> Iterator for IterTake<'a, T, A> {
+	type Item = Take<'a, T, A>;
 
 	/// # Panics
 	///
@@ -135,25 +228,84 @@ impl<'a, T: 'a> Iterator for IterTake<'a, T> {
 			panic!("called `IterTake::next` without destroying `Take` first")
 		}
 
-		if self.index == unsafe { self.inner.as_ref().len() } {
+		if self.guard.read == self.guard.back {
 			return None
 		}
 
 		self.panic = true;
 
 		Some(Take {
-			index: self.index,
+			index: self.guard.read,
+			from_back: false,
 			parent: NonNull::from(self),
 		})
 	}
 }
 
-pub struct Take<'a, T> {
-	parent: NonNull<IterTake<'a, T>>,
+impl<'a, T: 'a, A: Allocator> DoubleEndedIterator for IterTake<'a, T, A> {
+	/// Yields a [`Take`] for the last not-yet-visited element so callers can
+	/// remove from the tail. Front and back consumption meet in the middle and
+	/// then both return `None`.
+	///
+	/// # Panics
+	///
+	/// Panics on the same single-live-`Take` condition as [`IterTake::next`].
+	fn next_back(&mut self) -> Option<Self::Item> {
+		if self.panic {
+			panic!("called `IterTake::next` without destroying `Take` first")
+		}
+
+		if self.guard.read == self.guard.back {
+			return None
+		}
+
+		self.panic = true;
+
+		Some(Take {
+			index: self.guard.back - 1,
+			from_back: true,
+			parent: NonNull::from(self),
+		})
+	}
+}
+
+impl<'a, T, A: Allocator> Drop for DropGuard<'a, T, A> {
+	fn drop(&mut self) {
+		unsafe {
+			let vec = self.inner.as_mut();
+			let ptr = vec.as_mut_ptr();
+
+			// Shift whatever is left untouched down onto the write cursor, then
+			// slide the back-kept elements up against it, and publish the final
+			// length. On a normal finish the middle is empty; on an unwind it is
+			// whatever had not been visited yet.
+			let middle = self.back - self.read;
+			let back_kept = self.orig_len - self.rwrite;
+
+			if self.write < self.read && middle != 0 {
+				ptr::copy(ptr.add(self.read), ptr.add(self.write), middle);
+			}
+
+			let dst = self.write + middle;
+
+			if dst < self.rwrite && back_kept != 0 {
+				ptr::copy(ptr.add(self.rwrite), ptr.add(dst), back_kept);
+			}
+
+			vec.set_len(dst + back_kept);
+		}
+	}
+}
+
+pub struct Take<'a, T, A: Allocator = Global> {
+	parent: NonNull<IterTake<'a, T, A>>,
 	index: usize,
+	/// Whether this handle came from [`DoubleEndedIterator::next_back`], and so
+	/// removes from the tail rather than the front.
+	from_back: bool,
 }
 
-impl<'a, T> Take<'a, T> {
+impl<'a, T, A: Allocator> Take<'a, T, A> {
 	/// Removes and returns the current element from the vector while preserving the order
 	pub fn take(self) -> T {
 		unsafe {
@@ -164,7 +316,21 @@ impl<'a, T> Take<'a, T> {
 			debug_assert!(parent.panic, "a `Take` existed without the `panic` flag being set");
 
 			parent.panic = false;
-			parent.inner.as_mut().remove(this.index)
+
+			// Read the element out and leave a gap: the matching write cursor
+			// stays put so the next kept element is shifted into this slot.
+			let guard = &mut parent.guard;
+			let ptr = guard.inner.as_mut().as_mut_ptr();
+
+			if this.from_back {
+				let value = ptr::read(ptr.add(guard.back - 1));
+				guard.back -= 1;
+				value
+			} else {
+				let value = ptr::read(ptr.add(guard.read));
+				guard.read += 1;
+				value
+			}
 		}
 	}
 	/// Removes and returns the current element from the vector
@@ -179,12 +345,40 @@ impl<'a, T> Take<'a, T> {
 			debug_assert!(parent.panic, "a `Take` existed without the `panic` flag being set");
 
 			parent.panic = false;
-			parent.inner.as_mut().swap_remove(this.index)
+
+			let guard = &mut parent.guard;
+			let ptr = guard.inner.as_mut().as_mut_ptr();
+
+			// Fast path: drop an element from the opposite end of the untouched
+			// region into the hole instead of shifting. The moved element stays
+			// in the region and is the next one yielded from that end.
+			if this.from_back {
+				let value = ptr::read(ptr.add(guard.back - 1));
+
+				if guard.read + 1 < guard.back {
+					ptr::copy(ptr.add(guard.read), ptr.add(guard.back - 1), 1);
+					guard.read += 1;
+				} else {
+					guard.back -= 1;
+				}
+
+				value
+			} else {
+				let value = ptr::read(ptr.add(guard.read));
+
+				if guard.read + 1 < guard.back {
+					ptr::copy(ptr.add(guard.back - 1), ptr.add(guard.read), 1);
+				}
+
+				guard.back -= 1;
+
+				value
+			}
 		}
 	}
 }
 
-impl<'a, T> Drop for Take<'a, T> {
+impl<'a, T, A: Allocator> Drop for Take<'a, T, A> {
 	fn drop(&mut self) {
 		unsafe {
 			let parent = self.parent.as_mut();
@@ -192,27 +386,118 @@ impl<'a, T> Drop for Take<'a, T> {
 			debug_assert!(parent.panic, "a `Take` existed without the `panic` flag being set");
 
 			parent.panic = false;
-			parent.index += 1;
+
+			// The element is kept: shift it into the gap (if any) the taken
+			// elements have opened up on this end, then advance both cursors.
+			let guard = &mut parent.guard;
+			let ptr = guard.inner.as_mut().as_mut_ptr();
+
+			if self.from_back {
+				if guard.back < guard.rwrite {
+					ptr::copy(ptr.add(guard.back - 1), ptr.add(guard.rwrite - 1), 1);
+				}
+
+				guard.back -= 1;
+				guard.rwrite -= 1;
+			} else {
+				if guard.write < guard.read {
+					ptr::copy(ptr.add(guard.read), ptr.add(guard.write), 1);
+				}
+
+				guard.read += 1;
+				guard.write += 1;
+			}
 		}
 	}
 }
 
-impl<'a, T> Deref for Take<'a, T> {
+impl<'a, T, A: Allocator> Deref for Take<'a, T, A> {
 	type Target = T;
 
 	fn deref(&self) -> &Self::Target {
-		unsafe { self.parent.as_ref().inner.as_ref().get_unchecked(self.index) }
+		unsafe {
+			let vec = self.parent.as_ref().guard.inner.as_ref();
+			&*vec.as_ptr().add(self.index)
+		}
 	}
 }
 
-impl<'a, T> DerefMut for Take<'a, T> {
+impl<'a, T, A: Allocator> DerefMut for Take<'a, T, A> {
 	fn deref_mut(&mut self) -> &mut Self::Target {
 		unsafe {
 			let parent = self.parent.as_mut();
 
 			debug_assert!(parent.panic, "a `Take` existed without the `panic` flag being set");
 
-			parent.inner.as_mut().get_unchecked_mut(self.index)
+			&mut *parent.guard.inner.as_mut().as_mut_ptr().add(self.index)
+		}
+	}
+}
+
+/// Filter-and-remove iterator returned by [`IterTakeExt::extract_take`].
+///
+/// Reuses the same gap-buffer [`DropGuard`] as [`IterTake`]: each call to
+/// [`Iterator::next`] runs the predicate over the read cursor, reads matching
+/// elements out (leaving a gap) and shifts kept ones down onto the write
+/// cursor. When the `ExtractTake` is dropped the guard shifts whatever tail is
+/// left unvisited back into place and restores a correct length.
+pub struct ExtractTake<'a, T, F, A: Allocator = Global> {
+	guard: DropGuard<'a, T, A>,
+	pred: F,
+}
+
+impl<'a, T, F: FnMut(&mut T) -> bool, A: Allocator> ExtractTake<'a, T, F, A> {
+	pub(crate) fn new(inner: &'a mut Vec<T, A>, pred: F) -> Self {
+		let orig_len = inner.len();
+
+		// Same as `IterTake::new`: drop the length to `0` so an unwind inside
+		// `pred` can never observe a half-compacted buffer.
+		unsafe { inner.set_len(0) }
+
+		Self {
+			guard: DropGuard {
+				inner: NonNull::from(inner),
+				read: 0,
+				write: 0,
+				back: orig_len,
+				rwrite: orig_len,
+				orig_len,
+				_item: PhantomData,
+			},
+			pred,
+		}
+	}
+}
+
+impl<'a, T, F: FnMut(&mut T) -> bool, A: Allocator> Iterator for ExtractTake<'a, T, F, A> {
+	type Item = T;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		unsafe {
+			let guard = &mut self.guard;
+			let ptr = guard.inner.as_mut().as_mut_ptr();
+
+			while guard.read < guard.back {
+				let element = &mut *ptr.add(guard.read);
+
+				if (self.pred)(element) {
+					// Matched: read it out and leave a gap for the next kept
+					// element to be shifted into.
+					let value = ptr::read(ptr.add(guard.read));
+					guard.read += 1;
+					return Some(value)
+				}
+
+				// Kept: shift it down onto the write cursor and advance both.
+				if guard.write < guard.read {
+					ptr::copy(ptr.add(guard.read), ptr.add(guard.write), 1);
+				}
+
+				guard.read += 1;
+				guard.write += 1;
+			}
+
+			None
 		}
 	}
 }
@@ -242,6 +527,163 @@ mod tests {
 		assert_eq!(result, expected);
 	}
 
+	#[test]
+	fn compaction_preserves_order() {
+		let mut src = vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+
+		let taken: Vec<i32> = src
+			.iter_take()
+			.filter(|i| **i % 3 == 0)
+			.map(Take::take)
+			.collect();
+
+		assert_eq!(taken, vec![0, 3, 6, 9]);
+		assert_eq!(src, vec![1, 2, 4, 5, 7, 8]);
+	}
+
+	#[test]
+	fn take_unstable_fills_from_the_back() {
+		let mut src = vec![0, 1, 2, 3, 4];
+
+		let mut it = src.iter_take();
+		let first = it.next().unwrap();
+		assert_eq!(first.take_unstable(), 0);
+		drop(it);
+
+		assert_eq!(src.len(), 4);
+		assert!(src.contains(&4));
+		assert!(!src.contains(&0));
+	}
+
+	#[test]
+	fn works_with_a_custom_allocator() {
+		use std::alloc::System;
+
+		let mut src: Vec<i32, System> = Vec::new_in(System);
+		src.extend([0, 1, 2, 3, 4, 5]);
+
+		let taken: Vec<i32> = src
+			.iter_take()
+			.filter(|i| **i % 2 == 0)
+			.map(Take::take)
+			.collect();
+
+		assert_eq!(taken, vec![0, 2, 4]);
+		assert_eq!(src, [1, 3, 5]);
+	}
+
+	#[test]
+	fn extract_take_removes_matches_keeps_order() {
+		let mut src = vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+
+		let taken: Vec<i32> = src.extract_take(|i| *i % 3 == 0).collect();
+
+		assert_eq!(taken, vec![0, 3, 6, 9]);
+		assert_eq!(src, vec![1, 2, 4, 5, 7, 8]);
+	}
+
+	#[test]
+	fn extract_take_dropped_early_shifts_the_tail_back() {
+		let mut src = vec![0, 1, 2, 3, 4, 5];
+
+		let mut it = src.extract_take(|i| *i % 2 == 0);
+		assert_eq!(it.next(), Some(0));
+		assert_eq!(it.next(), Some(2));
+		drop(it);
+
+		assert_eq!(src, vec![1, 3, 4, 5]);
+	}
+
+	#[test]
+	fn double_ended_takes_from_both_ends() {
+		let mut src = vec![0, 1, 2, 3, 4, 5];
+		let mut it = src.iter_take();
+
+		let front = it.next().unwrap().take();
+		let back = it.next_back().unwrap().take();
+		drop(it);
+
+		assert_eq!(front, 0);
+		assert_eq!(back, 5);
+		assert_eq!(src, vec![1, 2, 3, 4]);
+	}
+
+	#[test]
+	fn rev_preserves_order_of_the_remainder() {
+		let mut src = vec![0, 1, 2, 3, 4, 5, 6, 7];
+
+		let taken: Vec<i32> = src
+			.iter_take()
+			.rev()
+			.filter(|i| **i % 2 == 0)
+			.map(Take::take)
+			.collect();
+
+		assert_eq!(taken, vec![6, 4, 2, 0]);
+		assert_eq!(src, vec![1, 3, 5, 7]);
+	}
+
+	#[test]
+	fn front_and_back_meet_in_the_middle() {
+		let mut src = vec![0, 1, 2, 3, 4];
+
+		let mut collected = Vec::new();
+		let mut it = src.iter_take();
+		let mut front = true;
+
+		loop {
+			let next = if front { it.next() } else { it.next_back() };
+			match next {
+				Some(take) => collected.push(Take::take(take)),
+				None => break,
+			}
+			front = !front;
+		}
+		drop(it);
+
+		assert_eq!(collected, vec![0, 4, 1, 3, 2]);
+		assert!(src.is_empty());
+	}
+
+	#[test]
+	fn unwinding_mid_iteration_leaves_vec_valid() {
+		use std::cell::Cell;
+		use std::panic::catch_unwind;
+		use std::panic::AssertUnwindSafe;
+
+		struct DropCounter<'a>(i32, &'a Cell<usize>);
+
+		impl Drop for DropCounter<'_> {
+			fn drop(&mut self) {
+				self.1.set(self.1.get() + 1);
+			}
+		}
+
+		let drops = Cell::new(0);
+		let mut src: Vec<DropCounter> = (0..6).map(|i| DropCounter(i, &drops)).collect();
+
+		let caught = catch_unwind(AssertUnwindSafe(|| {
+			src.iter_take().for_each(|item| {
+				if item.0 == 3 {
+					panic!("closure unwinds while holding a `Take`")
+				}
+				// every other element is kept
+			})
+		}));
+
+		assert!(caught.is_err());
+
+		// Nothing was dropped while unwinding: the live `Take` kept its element.
+		assert_eq!(drops.get(), 0);
+
+		// The unvisited tail was shifted back, so every element survives once.
+		let values: Vec<i32> = src.iter().map(|d| d.0).collect();
+		assert_eq!(values, vec![0, 1, 2, 3, 4, 5]);
+
+		drop(src);
+		assert_eq!(drops.get(), 6);
+	}
+
 	#[test]
 	#[should_panic = "called `IterTake::next` without destroying `Take` first"]
 	fn no_trivial_mut_alias() {